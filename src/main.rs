@@ -2,10 +2,15 @@
 #![no_main]
 #![feature(offset_of)]
 
+extern crate alloc;
+
+use core::alloc::GlobalAlloc;
+use core::alloc::Layout;
 use core::arch::asm;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::panic::PanicInfo;
+use core::ptr::null;
 use core::ptr::null_mut;
 use core::slice;
 
@@ -29,11 +34,110 @@ const EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data3: [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a],
 };
 
+// UEFI の戻り値。エラーコードは最上位ビットが立つ(0x8000_0000_0000_0000 | n)。
+const EFI_ERROR_BIT: u64 = 0x8000_0000_0000_0000;
+
+// ファームウェアが FFI 境界で返す生の状態値。仕様の列挙に無いコード(予約コードや
+// OEM 範囲)もそのまま保持できるよう、列挙ではなく newtype で受ける。列挙に変換すると
+// 未知の判別値で未定義動作になるため、ここでは値を検査せず into_result() で解釈する。
+#[repr(transparent)]
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[must_use]
-#[repr(u64)]
-enum EfiStatus {
-    Success = 0,
+struct EfiStatusRaw(u64);
+
+impl EfiStatusRaw {
+    const SUCCESS: u64 = 0;
+    const WARN_UNKNOWN_GLYPH: u64 = 1;
+    const WARN_DELETE_FAILURE: u64 = 2;
+    const WARN_WRITE_FAILURE: u64 = 3;
+    const WARN_BUFFER_TOO_SMALL: u64 = 4;
+    const WARN_STALE_DATA: u64 = 5;
+    const WARN_FILE_SYSTEM: u64 = 6;
+    const LOAD_ERROR: u64 = EFI_ERROR_BIT | 1;
+    const INVALID_PARAMETER: u64 = EFI_ERROR_BIT | 2;
+    const UNSUPPORTED: u64 = EFI_ERROR_BIT | 3;
+    const BAD_BUFFER_SIZE: u64 = EFI_ERROR_BIT | 4;
+    const BUFFER_TOO_SMALL: u64 = EFI_ERROR_BIT | 5;
+    const NOT_READY: u64 = EFI_ERROR_BIT | 6;
+    const DEVICE_ERROR: u64 = EFI_ERROR_BIT | 7;
+    const WRITE_PROTECTED: u64 = EFI_ERROR_BIT | 8;
+    const OUT_OF_RESOURCES: u64 = EFI_ERROR_BIT | 9;
+    const VOLUME_CORRUPTED: u64 = EFI_ERROR_BIT | 10;
+    const VOLUME_FULL: u64 = EFI_ERROR_BIT | 11;
+    const NO_MEDIA: u64 = EFI_ERROR_BIT | 12;
+    const MEDIA_CHANGED: u64 = EFI_ERROR_BIT | 13;
+    const NOT_FOUND: u64 = EFI_ERROR_BIT | 14;
+    const ACCESS_DENIED: u64 = EFI_ERROR_BIT | 15;
+    const NO_RESPONSE: u64 = EFI_ERROR_BIT | 16;
+    const NO_MAPPING: u64 = EFI_ERROR_BIT | 17;
+    const TIMEOUT: u64 = EFI_ERROR_BIT | 18;
+    const NOT_STARTED: u64 = EFI_ERROR_BIT | 19;
+    const ALREADY_STARTED: u64 = EFI_ERROR_BIT | 20;
+    const ABORTED: u64 = EFI_ERROR_BIT | 21;
+    const ICMP_ERROR: u64 = EFI_ERROR_BIT | 22;
+    const TFTP_ERROR: u64 = EFI_ERROR_BIT | 23;
+    const PROTOCOL_ERROR: u64 = EFI_ERROR_BIT | 24;
+    const INCOMPATIBLE_VERSION: u64 = EFI_ERROR_BIT | 25;
+    const SECURITY_VIOLATION: u64 = EFI_ERROR_BIT | 26;
+    const CRC_ERROR: u64 = EFI_ERROR_BIT | 27;
+    const END_OF_MEDIA: u64 = EFI_ERROR_BIT | 28;
+    const END_OF_FILE: u64 = EFI_ERROR_BIT | 31;
+    const INVALID_LANGUAGE: u64 = EFI_ERROR_BIT | 32;
+    const COMPROMISED_DATA: u64 = EFI_ERROR_BIT | 33;
+    const HTTP_ERROR: u64 = EFI_ERROR_BIT | 35;
+
+    // 成功を判定する。
+    fn is_success(self) -> bool {
+        self.0 == Self::SUCCESS
+    }
+
+    // Success を Ok に、その他を説明文付きの Err に変換する。これで boot service 呼び出しに
+    // ? を使って panic ループの代わりにエラーを伝播できる。
+    fn into_result(self) -> Result<()> {
+        match self.0 {
+            Self::SUCCESS => Ok(()),
+            Self::WARN_UNKNOWN_GLYPH => Err("EFI_WARN_UNKNOWN_GLYPH"),
+            Self::WARN_DELETE_FAILURE => Err("EFI_WARN_DELETE_FAILURE"),
+            Self::WARN_WRITE_FAILURE => Err("EFI_WARN_WRITE_FAILURE"),
+            Self::WARN_BUFFER_TOO_SMALL => Err("EFI_WARN_BUFFER_TOO_SMALL"),
+            Self::WARN_STALE_DATA => Err("EFI_WARN_STALE_DATA"),
+            Self::WARN_FILE_SYSTEM => Err("EFI_WARN_FILE_SYSTEM"),
+            Self::LOAD_ERROR => Err("EFI_LOAD_ERROR"),
+            Self::INVALID_PARAMETER => Err("EFI_INVALID_PARAMETER"),
+            Self::UNSUPPORTED => Err("EFI_UNSUPPORTED"),
+            Self::BAD_BUFFER_SIZE => Err("EFI_BAD_BUFFER_SIZE"),
+            Self::BUFFER_TOO_SMALL => Err("EFI_BUFFER_TOO_SMALL"),
+            Self::NOT_READY => Err("EFI_NOT_READY"),
+            Self::DEVICE_ERROR => Err("EFI_DEVICE_ERROR"),
+            Self::WRITE_PROTECTED => Err("EFI_WRITE_PROTECTED"),
+            Self::OUT_OF_RESOURCES => Err("EFI_OUT_OF_RESOURCES"),
+            Self::VOLUME_CORRUPTED => Err("EFI_VOLUME_CORRUPTED"),
+            Self::VOLUME_FULL => Err("EFI_VOLUME_FULL"),
+            Self::NO_MEDIA => Err("EFI_NO_MEDIA"),
+            Self::MEDIA_CHANGED => Err("EFI_MEDIA_CHANGED"),
+            Self::NOT_FOUND => Err("EFI_NOT_FOUND"),
+            Self::ACCESS_DENIED => Err("EFI_ACCESS_DENIED"),
+            Self::NO_RESPONSE => Err("EFI_NO_RESPONSE"),
+            Self::NO_MAPPING => Err("EFI_NO_MAPPING"),
+            Self::TIMEOUT => Err("EFI_TIMEOUT"),
+            Self::NOT_STARTED => Err("EFI_NOT_STARTED"),
+            Self::ALREADY_STARTED => Err("EFI_ALREADY_STARTED"),
+            Self::ABORTED => Err("EFI_ABORTED"),
+            Self::ICMP_ERROR => Err("EFI_ICMP_ERROR"),
+            Self::TFTP_ERROR => Err("EFI_TFTP_ERROR"),
+            Self::PROTOCOL_ERROR => Err("EFI_PROTOCOL_ERROR"),
+            Self::INCOMPATIBLE_VERSION => Err("EFI_INCOMPATIBLE_VERSION"),
+            Self::SECURITY_VIOLATION => Err("EFI_SECURITY_VIOLATION"),
+            Self::CRC_ERROR => Err("EFI_CRC_ERROR"),
+            Self::END_OF_MEDIA => Err("EFI_END_OF_MEDIA"),
+            Self::END_OF_FILE => Err("EFI_END_OF_FILE"),
+            Self::INVALID_LANGUAGE => Err("EFI_INVALID_LANGUAGE"),
+            Self::COMPROMISED_DATA => Err("EFI_COMPROMISED_DATA"),
+            Self::HTTP_ERROR => Err("EFI_HTTP_ERROR"),
+            // 仕様外・予約・OEM 範囲のコードも安全に扱う。
+            _ => Err("EFI_STATUS (unknown)"),
+        }
+    }
 }
 
 // メモリレイアウトを C 言語と互換性のある形式にするアトリビュート
@@ -41,31 +145,121 @@ enum EfiStatus {
 //UEFI 環境で利用可能なブートサービス関数へのアクセスを提供
 struct EfiBootServicesTable {
     // 予約領域で、UEFI の仕様に従って確保
-    _reserved0: [u64; 40],
+    _reserved0: [u64; 7],
+    // 現在のメモリマップを取得します。memory_map_size には必要・書き込んだバイト数が入ります。
+    get_memory_map: extern "win64" fn(
+        memory_map_size: *mut usize,
+        memory_map: *mut EfiMemoryDescriptor,
+        map_key: *mut usize,
+        descriptor_size: *mut usize,
+        descriptor_version: *mut u32,
+    ) -> EfiStatusRaw,
+    // 指定された種類・サイズのプールメモリを確保し、buffer にその先頭を書き込みます。
+    allocate_pool:
+        extern "win64" fn(pool_type: u32, size: usize, buffer: *mut *mut EfiVoid) -> EfiStatusRaw,
+    // allocate_pool で確保したメモリを解放します。
+    free_pool: extern "win64" fn(buffer: *mut EfiVoid) -> EfiStatusRaw,
+    _reserved1: [u64; 19],
+    // ブートサービスを終了し、OS ローダがメモリの所有権を得ます。
+    exit_boot_services:
+        extern "win64" fn(image_handle: EfiHandle, map_key: usize) -> EfiStatusRaw,
+    _reserved2: [u64; 10],
     // 指定されたプロトコル GUID に基づいてプロトコルインターフェースを検索します。
     locate_protocol: extern "win64" fn(
         protocol: *const EfiGuid,
         registration: *const EfiVoid,
         interface: *mut *mut EfiVoid,
-    ) -> EfiStatus,
+    ) -> EfiStatusRaw,
 }
 // Rust のコンパイラが構造体のメモリレイアウトを正しく設定していることを確認します。
+const _: () = assert!(offset_of!(EfiBootServicesTable, get_memory_map) == 56);
+const _: () = assert!(offset_of!(EfiBootServicesTable, allocate_pool) == 64);
+const _: () = assert!(offset_of!(EfiBootServicesTable, free_pool) == 72);
+const _: () = assert!(offset_of!(EfiBootServicesTable, exit_boot_services) == 232);
 const _: () = assert!(offset_of!(EfiBootServicesTable, locate_protocol) == 320);
 
+// AllocatePool に渡すメモリ種別。ローダが使う通常メモリ。
+const EFI_LOADER_DATA: u32 = 2;
+
+// GetMemoryMap が返すメモリ領域 1 件の記述子。
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct EfiMemoryDescriptor {
+    pub memory_type: u32,
+    pub phys_start: u64,
+    pub virt_start: u64,
+    pub num_pages: u64,
+    pub attribute: u64,
+}
+
+// Simple Text Output Protocol。コンソールへの文字列出力を提供する。
+#[repr(C)]
+struct EfiSimpleTextOutputProtocol {
+    reset: extern "win64" fn(this: *const EfiSimpleTextOutputProtocol, extended: bool) -> EfiStatusRaw,
+    // NUL 終端された UTF-16 文字列をコンソールへ出力する。
+    output_string:
+        extern "win64" fn(this: *const EfiSimpleTextOutputProtocol, string: *const u16) -> EfiStatusRaw,
+    // 残りのメンバ(test_string / query_mode など)は未使用。
+    _reserved0: [u64; 4],
+}
+const _: () = assert!(offset_of!(EfiSimpleTextOutputProtocol, output_string) == 8);
+
 #[repr(C)]
 struct EfiSystemTable {
-    _reserved0: [u64; 12],
+    _reserved0: [u64; 8],
+    // 予約ヘッダの直後に位置するコンソール出力プロトコル。
+    pub con_out: &'static EfiSimpleTextOutputProtocol,
+    _reserved1: [u64; 3],
     pub boot_services: &'static EfiBootServicesTable,
 }
+const _: () = assert!(offset_of!(EfiSystemTable, con_out) == 64);
 const _: () = assert!(offset_of!(EfiSystemTable, boot_services) == 96);
 
+// フレームバッファ 1 ピクセルのチャンネル配置。値は UEFI 仕様の
+// EFI_GRAPHICS_PIXEL_FORMAT に一致する。
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum PixelFormat {
+    RgbReserved8BitPerColor = 0,
+    BgrReserved8BitPerColor = 1,
+    BitMask = 2,
+    BltOnly = 3,
+}
+
+impl PixelFormat {
+    // ファームウェアが返す生の値を検査してから列挙へ変換する。範囲外(予約値など)は None。
+    // 生値のまま enum へ materialize すると不正な判別値で未定義動作になるため、ここを通す。
+    fn from_raw(raw: u32) -> Option<PixelFormat> {
+        match raw {
+            0 => Some(PixelFormat::RgbReserved8BitPerColor),
+            1 => Some(PixelFormat::BgrReserved8BitPerColor),
+            2 => Some(PixelFormat::BitMask),
+            3 => Some(PixelFormat::BltOnly),
+            _ => None,
+        }
+    }
+}
+
+// PixelFormat が BitMask のときに使う各チャンネルのビットマスク。
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct PixelBitmask {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub reserved: u32,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct EfiGraphicsOutputProtocolPixelInfo {
     version: u32,
     pub horizontal_resolution: u32,
     pub vertical_resolution: u32,
-    _padding0: [u32; 5],
+    // このモードのピクセル配置(生値)。PixelFormat::from_raw で検査してから使う。
+    pub pixel_format: u32,
+    // pixel_format が BitMask のときに各チャンネルが占めるビット位置を表す。
+    pub pixel_bitmask: PixelBitmask,
     pub pixels_per_scan_line: u32,
 }
 const _: () = assert!(size_of::<EfiGraphicsOutputProtocolPixelInfo>() == 36);
@@ -84,29 +278,443 @@ struct EfiGraphicsOutputProtocolMode<'a> {
 #[repr(C)]
 #[derive(Debug)]
 struct EfiGraphicsOutputProtocol<'a> {
-    reserved: [u64; 3],
+    // モード番号に対応するピクセル情報を問い合わせる。info にはファームウェアが確保した
+    // EfiGraphicsOutputProtocolPixelInfo へのポインタが書き込まれ(呼び出し側が FreePool で
+    // 解放する)、size_of_info に書き込まれたバイト数が返る。
+    query_mode: extern "win64" fn(
+        this: *const EfiGraphicsOutputProtocol,
+        mode_number: u32,
+        size_of_info: *mut usize,
+        info: *mut *const EfiGraphicsOutputProtocolPixelInfo,
+    ) -> EfiStatusRaw,
+    // 指定したモードに切り替える。成功すると mode の info と frame_buffer_base が更新される。
+    set_mode: extern "win64" fn(this: *const EfiGraphicsOutputProtocol, mode_number: u32) -> EfiStatusRaw,
+    // ファームウェアのブリッタ。矩形の塗りつぶしやスプライトの転送を高速に行う。
+    blt: extern "win64" fn(
+        this: *const EfiGraphicsOutputProtocol,
+        blt_buffer: *mut BltPixel,
+        operation: u32,
+        src_x: usize,
+        src_y: usize,
+        dst_x: usize,
+        dst_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> EfiStatusRaw,
     // 参照型のため、ライフタイム<'a>を指定している。
     pub mode: &'a EfiGraphicsOutputProtocolMode<'a>,
 }
 
+// Blt サービスが扱うピクセル。フレームバッファのフォーマットとは独立に BGRx 固定。
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct BltPixel {
+    pub blue: u8,
+    pub green: u8,
+    pub red: u8,
+    pub reserved: u8,
+}
+
+// Blt の操作種別。
+const EFI_BLT_VIDEO_FILL: u32 = 0;
+const EFI_BLT_VIDEO_TO_BLT_BUFFER: u32 = 1;
+const EFI_BLT_BUFFER_TO_VIDEO: u32 = 2;
+
+impl<'a> EfiGraphicsOutputProtocol<'a> {
+    // 利用可能なモードを全て走査し、描画可能なうちで最大の解像度を選んで切り替える。
+    // UEFI のデフォルトは小さなモードやグレースケールのことがあるため、実機で決定的に
+    // 最大解像度を得るために呼ぶ。set_mode 後は mode の info / frame_buffer_base が
+    // 変わるので、呼び出し側は再読み込みすること。
+    fn set_best_mode(&self) -> Result<()> {
+        let mut best_mode: Option<u32> = None;
+        let mut best_pixels: u32 = 0;
+        for i in 0..self.mode.max_mode {
+            let mut size_of_info: usize = 0;
+            let mut info: *const EfiGraphicsOutputProtocolPixelInfo = null();
+            let status = (self.query_mode)(self, i, &mut size_of_info, &mut info);
+            if status.into_result().is_err() || info.is_null() {
+                continue;
+            }
+            let pixel_info = unsafe { &*info };
+            // query_mode が書き込んだ情報が期待するレイアウトで、直接描画できるフォーマット
+            // (BitMask / BltOnly 以外)かどうかを判定する。
+            let renderable = size_of_info == size_of::<EfiGraphicsOutputProtocolPixelInfo>()
+                && matches!(
+                    PixelFormat::from_raw(pixel_info.pixel_format),
+                    Some(PixelFormat::RgbReserved8BitPerColor | PixelFormat::BgrReserved8BitPerColor)
+                );
+            let pixels = pixel_info.horizontal_resolution * pixel_info.vertical_resolution;
+            // query_mode はファームウェアが AllocatePool した PixelInfo を返すので、
+            // 読み終えたら FreePool で解放する(しないとモード数だけバッファが漏れる)。
+            if let Some(efi_system_table) = unsafe { EFI_SYSTEM_TABLE } {
+                let _ = (efi_system_table.boot_services.free_pool)(info as *mut EfiVoid);
+            }
+            if renderable && pixels > best_pixels {
+                best_pixels = pixels;
+                best_mode = Some(i);
+            }
+        }
+        let mode_number = best_mode.ok_or("No usable graphics mode found")?;
+        (self.set_mode)(self, mode_number).into_result()
+    }
+
+    // 画面上の矩形 (x, y, width, height) を単色で塗りつぶす。
+    fn video_fill(
+        &self,
+        color: BltPixel,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    ) -> Result<()> {
+        let mut color = color;
+        (self.blt)(self, &mut color, EFI_BLT_VIDEO_FILL, 0, 0, x, y, width, height, 0).into_result()
+    }
+
+    // バッファの矩形を画面へ転送する。delta はバッファ 1 行あたりのバイト数。
+    #[allow(clippy::too_many_arguments)]
+    fn buffer_to_video(
+        &self,
+        buf: &[BltPixel],
+        src_x: usize,
+        src_y: usize,
+        dst_x: usize,
+        dst_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> Result<()> {
+        (self.blt)(
+            self,
+            buf.as_ptr() as *mut BltPixel,
+            EFI_BLT_BUFFER_TO_VIDEO,
+            src_x,
+            src_y,
+            dst_x,
+            dst_y,
+            width,
+            height,
+            delta,
+        )
+        .into_result()
+    }
+
+    // 画面の矩形をバッファへ読み出す。delta はバッファ 1 行あたりのバイト数。
+    #[allow(clippy::too_many_arguments)]
+    fn video_to_buffer(
+        &self,
+        buf: &mut [BltPixel],
+        src_x: usize,
+        src_y: usize,
+        dst_x: usize,
+        dst_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> Result<()> {
+        (self.blt)(
+            self,
+            buf.as_mut_ptr(),
+            EFI_BLT_VIDEO_TO_BLT_BUFFER,
+            src_x,
+            src_y,
+            dst_x,
+            dst_y,
+            width,
+            height,
+            delta,
+        )
+        .into_result()
+    }
+}
+
 fn locate_graphic_protocol<'a>(
     efi_system_table: &EfiSystemTable,
 ) -> Result<&'a EfiGraphicsOutputProtocol<'a>> {
     let mut graphic_output_protocol = null_mut::<EfiGraphicsOutputProtocol>();
 
-    let status = (efi_system_table.boot_services.locate_protocol)(
+    (efi_system_table.boot_services.locate_protocol)(
         &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
         null_mut::<EfiVoid>(),
         &mut graphic_output_protocol as *mut *mut EfiGraphicsOutputProtocol as *mut *mut EfiVoid,
-    );
-    if status != EfiStatus::Success {
-        panic!("Failed to locate Graphics Output Protocol: {:?}", status);
-        //return Err("Failed to locate Graphics Output Protocol");
-    }
+    )
+    .into_result()?;
 
     Ok(unsafe { &*graphic_output_protocol })
 }
 
+// フレームバッファへの描画をピクセルフォーマットとストライドを考慮して行うラッパ。
+struct VramBufferInfo<'a> {
+    buf: &'a mut [u32],
+    pixel_format: PixelFormat,
+    pixel_bitmask: PixelBitmask,
+    width: usize,
+    height: usize,
+    // 1 行あたりのピクセル数。width と異なることがあるのでインデックス計算に使う。
+    pixels_per_scan_line: usize,
+}
+
+impl<'a> VramBufferInfo<'a> {
+    // 現在のモードからフレームバッファ情報を取り出す。
+    fn from_gop(gop: &EfiGraphicsOutputProtocol) -> VramBufferInfo<'a> {
+        let mode = gop.mode;
+        let info = mode.info;
+        let buf = unsafe {
+            slice::from_raw_parts_mut(
+                mode.frame_buffer_base as *mut u32,
+                mode.frame_buffer_size / size_of::<u32>(),
+            )
+        };
+        VramBufferInfo {
+            buf,
+            // 生値を検査して列挙へ。描画できない/未知の値は BltOnly 扱いにしておく。
+            pixel_format: PixelFormat::from_raw(info.pixel_format).unwrap_or(PixelFormat::BltOnly),
+            pixel_bitmask: info.pixel_bitmask,
+            width: info.horizontal_resolution as usize,
+            height: info.vertical_resolution as usize,
+            pixels_per_scan_line: info.pixels_per_scan_line as usize,
+        }
+    }
+
+    // RGB 各チャンネルを、現在のピクセルフォーマットに合わせて u32 に詰める。
+    fn pack_color(&self, r: u8, g: u8, b: u8) -> u32 {
+        match self.pixel_format {
+            PixelFormat::RgbReserved8BitPerColor => {
+                (b as u32) << 16 | (g as u32) << 8 | (r as u32)
+            }
+            PixelFormat::BgrReserved8BitPerColor => {
+                (r as u32) << 16 | (g as u32) << 8 | (b as u32)
+            }
+            PixelFormat::BitMask => {
+                pack_channel(r, self.pixel_bitmask.red)
+                    | pack_channel(g, self.pixel_bitmask.green)
+                    | pack_channel(b, self.pixel_bitmask.blue)
+            }
+            // 直接描画できないフォーマットでは色を作りようがないので 0 を返す。
+            PixelFormat::BltOnly => 0,
+        }
+    }
+
+    // 画面全体を指定色で塗りつぶす。
+    fn fill(&mut self, color: u32) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                self.buf[y * self.pixels_per_scan_line + x] = color;
+            }
+        }
+    }
+
+    // 1 ピクセルを描画する。stride を尊重して pixels_per_scan_line でインデックスする。
+    fn draw_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            self.buf[y * self.pixels_per_scan_line + x] = color;
+        }
+    }
+}
+
+// 8bit のチャンネル値を、指定ビットマスクが示す位置へシフトして詰める。
+fn pack_channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    // マスクの最下位ビット位置へ 8bit 値の上位ビットを合わせる。
+    let shift = mask.trailing_zeros();
+    let width = (mask >> shift).count_ones();
+    let value = if width >= 8 {
+        (value as u32) << (width - 8)
+    } else {
+        (value as u32) >> (8 - width)
+    };
+    (value << shift) & mask
+}
+
+// print! / println! から参照するために捕捉しておくシステムテーブル。
+// efi_main の開始時に一度だけ設定される。
+static mut EFI_SYSTEM_TABLE: Option<&'static EfiSystemTable> = None;
+
+// グローバルなシステムテーブルポインタを登録する。
+fn register_global_system_table(efi_system_table: &'static EfiSystemTable) {
+    unsafe {
+        EFI_SYSTEM_TABLE = Some(efi_system_table);
+    }
+}
+
+// AllocatePool / FreePool を裏で使うグローバルアロケータ。
+// これにより alloc の Vec / String / Box が利用可能になる。
+struct UefiAllocator;
+
+unsafe impl GlobalAlloc for UefiAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let efi_system_table = match unsafe { EFI_SYSTEM_TABLE } {
+            Some(t) => t,
+            None => return null_mut(),
+        };
+        // 返却ブロックの直前に元ポインタを保存するヘッダ語と、アライメント調整分を余分に確保する。
+        let header = size_of::<usize>();
+        let total = layout.size() + layout.align() + header;
+        let mut buffer = null_mut::<EfiVoid>();
+        let status =
+            (efi_system_table.boot_services.allocate_pool)(EFI_LOADER_DATA, total, &mut buffer);
+        if status.into_result().is_err() || buffer.is_null() {
+            return null_mut();
+        }
+        let raw = buffer as usize;
+        // ヘッダ分を空けた上でアライメントに切り上げる。
+        let aligned = (raw + header + layout.align() - 1) & !(layout.align() - 1);
+        // 返却ブロックの直前の語に AllocatePool が返した元ポインタを記録する。
+        unsafe { *((aligned - header) as *mut usize) = raw };
+        aligned as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let efi_system_table = match unsafe { EFI_SYSTEM_TABLE } {
+            Some(t) => t,
+            None => return,
+        };
+        let header = size_of::<usize>();
+        // ヘッダ語から元ポインタを復元して FreePool へ渡す。
+        let raw = unsafe { *((ptr as usize - header) as *mut usize) };
+        let _ = (efi_system_table.boot_services.free_pool)(raw as *mut EfiVoid);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: UefiAllocator = UefiAllocator;
+
+// Simple Text Output Protocol を core::fmt::Write として扱うラッパ。
+struct EfiConsole {
+    con_out: &'static EfiSimpleTextOutputProtocol,
+}
+
+impl EfiConsole {
+    // 登録済みのシステムテーブルからコンソールを取得する。未登録なら None。
+    fn new() -> Option<EfiConsole> {
+        let efi_system_table = unsafe { EFI_SYSTEM_TABLE }?;
+        Some(EfiConsole {
+            con_out: efi_system_table.con_out,
+        })
+    }
+
+    // 溜まった UTF-16 を NUL 終端して出力し、バッファを空にする。
+    fn flush(&self, buf: &mut [u16], len: &mut usize) {
+        if *len == 0 {
+            return;
+        }
+        buf[*len] = 0;
+        let _ = (self.con_out.output_string)(self.con_out, buf.as_ptr());
+        *len = 0;
+    }
+}
+
+impl core::fmt::Write for EfiConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // 1 チャンクあたりの文字数。CR と 2 ユニット文字、NUL の分だけ余裕を持たせる。
+        const CHUNK: usize = 64;
+        let mut buf = [0u16; CHUNK + 3];
+        let mut len = 0;
+        for c in s.chars() {
+            // UEFI コンソールは CRLF を期待するため、改行を変換する。
+            if c == '\n' {
+                buf[len] = b'\r' as u16;
+                len += 1;
+            }
+            len += c.encode_utf16(&mut buf[len..len + 2]).len();
+            if len >= CHUNK {
+                self.flush(&mut buf, &mut len);
+            }
+        }
+        self.flush(&mut buf, &mut len);
+        Ok(())
+    }
+}
+
+// print! / println! の実体。コンソールが取得できなければ黙って捨てる。
+fn _print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    if let Some(mut console) = EfiConsole::new() {
+        let _ = console.write_fmt(args);
+    }
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+// ブートサービスを終了し、メモリの所有権を取得する。
+// 確保によって map_key が無効化されるため、最後の get_memory_map と exit_boot_services の
+// 間では確保を行わない。そのため最初の見積もりでスラックを持たせてバッファを確保しておく。
+// マップが途中で変わって exit_boot_services が失敗した場合は、同じバッファへ取り直して
+// 数回まで再試行する。成功すると確保済みバッファ上の記述子スライスを返す。
+fn exit_boot_services(image_handle: EfiHandle) -> Result<&'static [EfiMemoryDescriptor]> {
+    let efi_system_table = unsafe { EFI_SYSTEM_TABLE }.ok_or("System table not registered")?;
+    let boot_services = efi_system_table.boot_services;
+
+    // まず必要なバッファサイズを調べる。
+    let mut memory_map_size: usize = 0;
+    let mut map_key: usize = 0;
+    let mut descriptor_size: usize = 0;
+    let mut descriptor_version: u32 = 0;
+    let _ = (boot_services.get_memory_map)(
+        &mut memory_map_size,
+        null_mut(),
+        &mut map_key,
+        &mut descriptor_size,
+        &mut descriptor_version,
+    );
+    // 以降の確保でマップが数エントリ増えることを見越してスラックを足す。
+    memory_map_size += descriptor_size * 8;
+
+    // この確保以降、exit_boot_services までは追加の確保を行わない。
+    let mut buffer = alloc::vec![0u8; memory_map_size];
+
+    const MAX_ATTEMPTS: usize = 4;
+    for _ in 0..MAX_ATTEMPTS {
+        let mut map_size = buffer.len();
+        (boot_services.get_memory_map)(
+            &mut map_size,
+            buffer.as_mut_ptr() as *mut EfiMemoryDescriptor,
+            &mut map_key,
+            &mut descriptor_size,
+            &mut descriptor_version,
+        )
+        .into_result()?;
+        // descriptor_size はファームウェアが決める実際のストライドで、
+        // size_of::<EfiMemoryDescriptor>() より大きいことが多い(OVMF では 48)。
+        if descriptor_size < size_of::<EfiMemoryDescriptor>() {
+            return Err("Unexpected memory descriptor size");
+        }
+        if (boot_services.exit_boot_services)(image_handle, map_key).is_success() {
+            // 記述子は descriptor_size 間隔で並ぶ。EfiMemoryDescriptor の詰んだ配列として
+            // 扱えるよう、確保済みバッファ内でその場で詰め直す(後方への移動なので安全)。
+            // ブートサービス終了後は追加の確保ができないため、既存バッファを再利用する。
+            let count = map_size / descriptor_size;
+            let base = buffer.as_mut_ptr();
+            for i in 0..count {
+                let desc = unsafe {
+                    core::ptr::read_unaligned(
+                        base.add(i * descriptor_size) as *const EfiMemoryDescriptor,
+                    )
+                };
+                unsafe { (base as *mut EfiMemoryDescriptor).add(i).write(desc) };
+            }
+            let ptr = buffer.as_ptr() as *const EfiMemoryDescriptor;
+            // ブートサービス終了後は FreePool が使えないため解放させない。
+            core::mem::forget(buffer);
+            return Ok(unsafe { slice::from_raw_parts(ptr, count) });
+        }
+        // 失敗した場合はマップが変わったので、同じバッファへ取り直して再試行する。
+    }
+    Err("Failed to exit boot services")
+}
+
 pub fn hlt() {
     unsafe {
         asm!("hlt");
@@ -114,22 +722,52 @@ pub fn hlt() {
 }
 
 #[no_mangle]
-fn efi_main(_image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+fn efi_main(image_handle: EfiHandle, efi_system_table: &'static EfiSystemTable) {
+    // print! / println! から参照できるようにシステムテーブルを登録する。
+    register_global_system_table(efi_system_table);
     // UEFI システムテーブルを使用して、グラフィックス出力プロトコルを取得する
     let efi_graphics_output_protocol = locate_graphic_protocol(efi_system_table).unwrap();
-    // VRAM (ビデオメモリ) の取得
-    let vram_addr = efi_graphics_output_protocol.mode.frame_buffer_base;
-    let vram_byte_size = efi_graphics_output_protocol.mode.frame_buffer_size;
+    // 描画可能な最大解像度へ切り替える。切り替え後は mode の内容が変わる。
+    efi_graphics_output_protocol.set_best_mode().unwrap();
+    // フレームバッファ情報の取得。set_mode 後に変化するため、ここで改めて読み込む。
+    let mut vram = VramBufferInfo::from_gop(efi_graphics_output_protocol);
+    // フレームバッファ全体を赤色で塗りつぶす。色はピクセルフォーマットに従って詰める。
+    let red = vram.pack_color(0xff, 0x00, 0x00);
+    vram.fill(red);
+    // 左上に白い点を 1 つ描く。
+    let white = vram.pack_color(0xff, 0xff, 0xff);
+    vram.draw_pixel(0, 0, white);
 
-    // 生ポインタから操作可能なスライスvramに変換。
-    let vram = unsafe {
-        slice::from_raw_parts_mut(vram_addr as *mut u32, vram_byte_size / size_of::<u32>())
+    // GOP の Blt サービスの利用例。緑の矩形を塗り、その一部を読み出して別の位置へ貼り付ける。
+    let green = BltPixel {
+        blue: 0,
+        green: 0xff,
+        red: 0,
+        reserved: 0,
     };
-    for e in vram {
-        // フレームバッファ内のすべてのピクセルを白色に設定
-        *e = 0xff0000;
-    }
-    //println!("Hello, world!");
+    efi_graphics_output_protocol
+        .video_fill(green, 0, 0, 64, 64)
+        .unwrap();
+    let mut sprite = alloc::vec![
+        BltPixel {
+            blue: 0,
+            green: 0,
+            red: 0,
+            reserved: 0,
+        };
+        32 * 32
+    ];
+    let delta = 32 * size_of::<BltPixel>();
+    efi_graphics_output_protocol
+        .video_to_buffer(&mut sprite, 0, 0, 0, 0, 32, 32, delta)
+        .unwrap();
+    efi_graphics_output_protocol
+        .buffer_to_video(&sprite, 0, 0, 128, 128, 32, 32, delta)
+        .unwrap();
+
+    println!("Hello, world!");
+    // ブートサービスを終了してメモリの所有権を得る。以降 UEFI の各サービスは使えない。
+    let _memory_map = exit_boot_services(image_handle).unwrap();
     loop {
         hlt()
     }